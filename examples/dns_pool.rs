@@ -1,7 +1,7 @@
 use std::net::ToSocketAddrs;
 
 lazy_static::lazy_static! {
-    static ref POOL: may_thread::ThreadPool<()> = may_thread::ThreadPool::new(||{}, 4);
+    static ref POOL: may_thread::ThreadPool<()> = may_thread::ThreadPool::new_simple(||{}, 4);
 }
 
 fn main() {
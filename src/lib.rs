@@ -36,7 +36,10 @@ mod pool;
 
 use std::mem;
 use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use may::coroutine;
 use may::sync::Blocker;
@@ -99,6 +102,260 @@ where
     }
 }
 
+/// Like `join`, but gives up waiting after `dur` and returns `None` instead
+/// of blocking the coroutine forever.
+///
+/// If the closure panics within `dur`, the panic data is still
+/// `resume_unwind` in the current context. On timeout the worker thread is
+/// left running in the background rather than being torn down, so the
+/// result slot is wrapped in an `Arc` to stay alive for its late write
+/// even after this function has already returned `None` -- be aware that
+/// `T`'s destructor may end up running on that worker thread instead of
+/// the caller's. Because the closure may keep running after this function
+/// returns, unlike `join` there's no rendezvous to guarantee a borrow
+/// outlives it, so `F` and `T` must be `'static`, same as `thread::spawn`.
+///
+///
+/// # Examples
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate may;
+/// extern crate may_thread;
+///
+/// use std::time::Duration;
+/// use may_thread::join_timeout;
+///
+/// fn main() {
+///     let j = go!(|| {
+///         let _result = join_timeout(
+///             || {
+///                 // ......
+///             },
+///             Duration::from_secs(1),
+///         );
+///     });
+///     j.join();
+/// }
+/// ```
+///
+pub fn join_timeout<F, T>(f: F, dur: Duration) -> Option<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let blocker = Blocker::current();
+    let ret = Arc::new(Mutex::new(None));
+    let ret_worker = ret.clone();
+    let blocker_worker = blocker.clone();
+
+    // `f` is `'static`, so unlike `join` we don't need the `spawn_unsafe`
+    // lifetime-extension trick here
+    let _join = thread::spawn(move || {
+        *ret_worker.lock().unwrap() = Some(panic::catch_unwind(panic::AssertUnwindSafe(f)));
+        blocker_worker.unpark();
+    });
+    // we can't use the `join()` API here, it will block the thread!
+    // we need catch the panic inside `f`, or we may wait forever!
+    match blocker.park(Some(dur)) {
+        Ok(_) => match ret.lock().unwrap().take().expect("ret not set") {
+            Ok(ret) => Some(ret),
+            Err(panic) => panic::resume_unwind(panic),
+        },
+        Err(coroutine::ParkError::Timeout) => None,
+        Err(coroutine::ParkError::Canceled) => {
+            // cancel happened, we do nothing here
+            coroutine::trigger_cancel_panic();
+        }
+    }
+}
+
+/// Run two closures in parallel, each on its own freshly spawned worker
+/// thread, and collect both results; the current coroutine parks until both
+/// have finished.
+///
+/// This gives coroutines a structured fork-join primitive for CPU-bound
+/// work without manually wiring up two `join` calls and two `Blocker`s. If
+/// both closures panic, `a`'s panic is the one `resume_unwind` here.
+///
+///
+/// # Examples
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate may;
+/// extern crate may_thread;
+///
+/// use may_thread::join2;
+///
+/// fn main() {
+///     let j = go!(|| {
+///         let (ra, rb) = join2(|| 1 + 1, || 2 + 2);
+///         assert_eq!(ra, 2);
+///         assert_eq!(rb, 4);
+///     });
+///     j.join();
+/// }
+/// ```
+///
+pub fn join2<'a, A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send + 'a,
+    B: FnOnce() -> RB + Send + 'a,
+    RA: Send,
+    RB: Send,
+{
+    let blocker = Blocker::current();
+    // counts down from 2 to 0; whichever closure finishes last wakes us up
+    let left = AtomicUsize::new(2);
+    let mut ret_a = None;
+    let mut ret_b = None;
+
+    // we can't run `b` inline on this thread, it will block the scheduler
+    // thread for as long as `b` takes; both `a` and `b` must be offloaded
+    let _join_a = unsafe {
+        spawn_unsafe(|| {
+            ret_a = Some(panic::catch_unwind(panic::AssertUnwindSafe(a)));
+            if left.fetch_sub(1, Ordering::AcqRel) == 1 {
+                blocker.unpark();
+            }
+        })
+    };
+    let _join_b = unsafe {
+        spawn_unsafe(|| {
+            ret_b = Some(panic::catch_unwind(panic::AssertUnwindSafe(b)));
+            if left.fetch_sub(1, Ordering::AcqRel) == 1 {
+                blocker.unpark();
+            }
+        })
+    };
+
+    // we can't use the `join()` API here, it will block the thread!
+    // we need catch the panic inside `a`/`b`, or we may wait forever!
+    match blocker.park(None) {
+        Ok(_) => {}
+        Err(_) => coroutine::trigger_cancel_panic(),
+    }
+
+    // prefer `a`'s panic if both closures panicked
+    match (ret_a.expect("ret not set"), ret_b.expect("ret not set")) {
+        (Err(panic), _) => panic::resume_unwind(panic),
+        (_, Err(panic)) => panic::resume_unwind(panic),
+        (Ok(ra), Ok(rb)) => (ra, rb),
+    }
+}
+
+/// RAII handle returned by `spawn`.
+///
+/// By default, dropping a `WorkGuard` parks the current coroutine until
+/// the spawned closure finishes, just like `join`, and `resume_unwind`s
+/// any panic it produced. Call `detach` to let the work run unobserved
+/// instead, or `join` to wait for it explicitly and get the result.
+pub struct WorkGuard<T> {
+    state: Option<WorkGuardState<T>>,
+}
+
+struct WorkGuardState<T> {
+    blocker: Arc<Blocker>,
+    ret: Arc<Mutex<Option<thread::Result<T>>>>,
+}
+
+impl<T> WorkGuard<T> {
+    /// let the spawned work run to completion unobserved; this coroutine
+    /// does not wait for it
+    pub fn detach(mut self) {
+        self.state.take();
+    }
+
+    /// park the current coroutine until the spawned work finishes and
+    /// return its result, leaving a panic for the caller to handle instead
+    /// of resuming it here
+    pub fn join(mut self) -> thread::Result<T> {
+        let state = self.state.take().expect("WorkGuard state missing");
+        Self::wait(state)
+    }
+
+    fn wait(state: WorkGuardState<T>) -> thread::Result<T> {
+        // we can't use the `join()` API here, it will block the thread!
+        match state.blocker.park(None) {
+            Ok(_) => state.ret.lock().unwrap().take().expect("ret not set"),
+            Err(_) => {
+                // impossible be a timeout err
+                // cancel happened, we do nothing here
+                coroutine::trigger_cancel_panic();
+            }
+        }
+    }
+}
+
+impl<T> Drop for WorkGuard<T> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            if let Err(panic) = Self::wait(state) {
+                // resuming a panic while already unwinding would abort the
+                // process instead of propagating a catchable panic, so just
+                // drop the spawned work's panic on the floor in that case
+                if !thread::panicking() {
+                    panic::resume_unwind(panic);
+                }
+            }
+        }
+    }
+}
+
+/// Launch the given closure on a freshly spawned worker thread and return
+/// a `WorkGuard` for it.
+///
+/// Unlike `join`, the spawned work can outlive the current coroutine step:
+/// call `detach` on the guard to let it run in the background, or call
+/// `join` on it to wait for the result explicitly. Dropping the guard
+/// without either waits for the work to finish, like `join`. Because
+/// `detach` lets the closure keep running with no rendezvous at all,
+/// `F` and `T` must be `'static`, same as `thread::spawn`.
+///
+///
+/// # Examples
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate may;
+/// extern crate may_thread;
+///
+/// use may_thread::spawn;
+///
+/// fn main() {
+///     let j = go!(|| {
+///         spawn(|| {
+///             // fire and forget
+///         })
+///         .detach();
+///     });
+///     j.join();
+/// }
+/// ```
+///
+pub fn spawn<F, T>(f: F) -> WorkGuard<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let blocker = Blocker::current();
+    let ret = Arc::new(Mutex::new(None));
+    let ret_worker = ret.clone();
+    let blocker_worker = blocker.clone();
+
+    // `f` is `'static`, so unlike `join` we can spawn it directly without
+    // the `spawn_unsafe` lifetime-extension trick
+    let _join = thread::spawn(move || {
+        *ret_worker.lock().unwrap() = Some(panic::catch_unwind(panic::AssertUnwindSafe(f)));
+        blocker_worker.unpark();
+    });
+
+    WorkGuard {
+        state: Some(WorkGuardState { blocker, ret }),
+    }
+}
+
 /// Like `thread::spawn`, but without the closure bounds.
 unsafe fn spawn_unsafe<'a, F>(f: F) -> thread::JoinHandle<()>
 where
@@ -131,4 +388,72 @@ mod tests {
         let panic = panic::catch_unwind(|| join(|| panic!("panic")));
         assert_eq!(panic.is_err(), true);
     }
+
+    #[test]
+    fn join_timeout_ok() {
+        let ret = join_timeout(|| 10, Duration::from_secs(1));
+        assert_eq!(ret, Some(10));
+    }
+
+    #[test]
+    fn join_timeout_expires() {
+        let ret = join_timeout(
+            || {
+                thread::sleep(Duration::from_secs(1));
+                10
+            },
+            Duration::from_millis(10),
+        );
+        assert_eq!(ret, None);
+    }
+
+    #[test]
+    fn join2_test() {
+        let (a, b) = join2(|| 1 + 1, || 2 + 2);
+        assert_eq!(a, 2);
+        assert_eq!(b, 4);
+    }
+
+    #[test]
+    fn spawn_join() {
+        let ret = spawn(|| 10).join();
+        assert_eq!(ret.unwrap(), 10);
+    }
+
+    #[test]
+    fn spawn_detach() {
+        // just make sure a detached guard doesn't block the coroutine
+        spawn(|| thread::sleep(Duration::from_secs(1))).detach();
+    }
+
+    #[test]
+    fn spawn_drop_resumes_panic() {
+        let panic = panic::catch_unwind(|| {
+            let _guard = spawn(|| panic!("panic"));
+        });
+        assert_eq!(panic.is_err(), true);
+    }
+
+    #[test]
+    fn spawn_drop_during_unwind_does_not_abort() {
+        // dropping a guard for a panicked closure while already unwinding
+        // from an unrelated panic must not try to resume_unwind a second
+        // panic -- that would abort the process instead of propagating
+        let guard = spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            panic!("background panic");
+        });
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            struct DropsGuard(Option<WorkGuard<()>>);
+            impl Drop for DropsGuard {
+                fn drop(&mut self) {
+                    // guard is dropped here while unwinding from the panic below
+                    self.0.take();
+                }
+            }
+            let _d = DropsGuard(Some(guard));
+            panic!("foreground panic");
+        }));
+        assert_eq!(res.is_err(), true);
+    }
 }
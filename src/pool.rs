@@ -1,16 +1,203 @@
 //! thread pool that share the same work queue
 //! when init thread pool, we need to tell it how to init the associated data
 //! ThreadPool
+//!
+//! Following rayon-core's registry design, each worker owns a local LIFO
+//! deque that it pushes/pops from without contention, a shared `Injector`
+//! feeds idle workers and receives submissions made from coroutine
+//! context, and idle workers steal (FIFO) from the back of other workers'
+//! deques when their own is empty. This avoids every `join`/`spawn` call
+//! contending on a single shared queue.
 
 use may::coroutine;
-use may::sync::mpmc;
+use may::sync::Blocker;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
+
+type Job<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+thread_local! {
+    // the index of the worker running on the current OS thread, if any
+    static CURRENT_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// the global injector queue: fed by submissions from coroutine context,
+/// drained by any idle worker
+struct Injector<S> {
+    jobs: Mutex<VecDeque<Job<S>>>,
+}
+
+impl<S> Injector<S> {
+    fn new() -> Self {
+        Injector {
+            jobs: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn push(&self, job: Job<S>) {
+        self.jobs.lock().unwrap().push_back(job);
+    }
+
+    fn pop(&self) -> Option<Job<S>> {
+        self.jobs.lock().unwrap().pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
+}
+
+/// a single worker's local deque: the owner pushes/pops from the front
+/// (LIFO), thieves steal from the back (FIFO)
+struct Deque<S> {
+    jobs: Mutex<VecDeque<Job<S>>>,
+}
+
+impl<S> Deque<S> {
+    fn new() -> Self {
+        Deque {
+            jobs: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn push(&self, job: Job<S>) {
+        self.jobs.lock().unwrap().push_front(job);
+    }
+
+    fn pop(&self) -> Option<Job<S>> {
+        self.jobs.lock().unwrap().pop_front()
+    }
+
+    fn steal(&self) -> Option<Job<S>> {
+        self.jobs.lock().unwrap().pop_back()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
+}
+
+/// a handle other workers use to steal from one worker's local deque
+struct Stealer<S>(Arc<Deque<S>>);
+
+impl<S> Stealer<S> {
+    fn steal(&self) -> Option<Job<S>> {
+        self.0.steal()
+    }
+}
+
+/// parks idle workers and wakes them when new work may be available,
+/// instead of having them busy-poll the deques
+struct Sleep {
+    lock: Mutex<()>,
+    condvar: Condvar,
+    sleeping: AtomicUsize,
+}
+
+impl Sleep {
+    fn new() -> Self {
+        Sleep {
+            lock: Mutex::new(()),
+            condvar: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+        }
+    }
+
+    // park this worker for a short while, or until woken by `wake_one`/`wake_all`
+    fn sleep(&self) {
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        let guard = self.lock.lock().unwrap();
+        // bounded wait: a missed wakeup just costs one extra poll, never a stall
+        let _ = self
+            .condvar
+            .wait_timeout(guard, Duration::from_millis(10))
+            .unwrap();
+        self.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn wake_one(&self) {
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.condvar.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.condvar.notify_all();
+    }
+}
+
+/// the state shared by all workers of a `ThreadPool`
+struct Registry<S> {
+    injector: Injector<S>,
+    stealers: Vec<Stealer<S>>,
+    locals: Vec<Arc<Deque<S>>>,
+    sleep: Sleep,
+    shutdown: AtomicBool,
+}
+
+impl<S> Registry<S> {
+    // find the next job for worker `idx`: own deque, then the injector,
+    // then steal from another worker
+    fn find_job(&self, idx: usize) -> Option<Job<S>> {
+        if let Some(job) = self.locals[idx].pop() {
+            return Some(job);
+        }
+        if let Some(job) = self.injector.pop() {
+            return Some(job);
+        }
+        let n = self.stealers.len();
+        for i in 1..n {
+            let victim = (idx + i) % n;
+            if let Some(job) = self.stealers[victim].steal() {
+                return Some(job);
+            }
+        }
+        None
+    }
+
+    fn is_drained(&self) -> bool {
+        self.injector.is_empty() && self.locals.iter().all(|d| d.is_empty())
+    }
+
+    // submit a job for execution: a job submitted from inside a job
+    // already running on a worker goes to that worker's own deque,
+    // everything else (submissions from coroutine context) goes to the
+    // shared injector
+    fn submit(&self, job: Job<S>) {
+        match CURRENT_WORKER.with(Cell::get) {
+            Some(idx) => self.locals[idx].push(job),
+            None => self.injector.push(job),
+        }
+        self.sleep.wake_one();
+    }
+}
+
+fn run_worker<S>(idx: usize, mut state: S, registry: Arc<Registry<S>>) {
+    CURRENT_WORKER.with(|w| w.set(Some(idx)));
+    loop {
+        match registry.find_job(idx) {
+            Some(job) => job(&mut state),
+            None => {
+                if registry.shutdown.load(Ordering::Acquire) && registry.is_drained() {
+                    break;
+                }
+                registry.sleep.sleep();
+            }
+        }
+    }
+}
 
 /// Thread pool that can run closures in parallel
 pub struct ThreadPool<S> {
-    // all worker thread share the same mpmc queue
-    // used to push works into the queue
-    queue_tx: mpmc::Sender<Box<dyn FnOnce(&mut S) + Send>>,
+    // shared injector / per-worker deques / sleep state, following
+    // rayon-core's registry design
+    registry: Arc<Registry<S>>,
 
     // thread pool handles
     threads: Vec<Option<thread::JoinHandle<()>>>,
@@ -22,30 +209,47 @@ unsafe impl<S> Sync for ThreadPool<S> {}
 // S should be created in thread in parallel
 // thus not need to be Send, but that need f to be Send and Sync
 impl<S: Send + 'static> ThreadPool<S> {
-    /// create a thread pool with the specified state initializer and pool size
+    /// create a thread pool with the specified state initializer and pool
+    /// size, calling `f(i)` to build the state for worker `i` so that
+    /// per-worker state (a cache shard, a pinned core, a numbered file...)
+    /// can be told workers apart
     pub fn new<F>(f: F, size: usize) -> Self
     where
-        F: Fn() -> S,
+        F: Fn(usize) -> S,
     {
+        let locals: Vec<_> = (0..size).map(|_| Arc::new(Deque::new())).collect();
+        let stealers = locals.iter().cloned().map(Stealer).collect();
+
+        let registry = Arc::new(Registry {
+            injector: Injector::new(),
+            stealers,
+            locals,
+            sleep: Sleep::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
         let mut threads = Vec::with_capacity(size);
-        let (tx, rx) = mpmc::channel::<Box<dyn FnOnce(&mut S) + Send>>();
-        for _i in 0..size {
+        for i in 0..size {
             // each thread has a internal state
-            let mut state = f();
-            let rx = rx.clone();
-            let thread = thread::spawn(move || {
-                for work in rx.into_iter() {
-                    // execute the work
-                    work(&mut state);
-                }
-            });
+            let state = f(i);
+            let registry = registry.clone();
+            let thread = thread::Builder::new()
+                .name(format!("may_thread-pool-{}", i))
+                .spawn(move || run_worker(i, state, registry))
+                .expect("failed to spawn worker thread");
             threads.push(Some(thread));
         }
 
-        ThreadPool {
-            queue_tx: tx,
-            threads,
-        }
+        ThreadPool { registry, threads }
+    }
+
+    /// like `new`, but for state initializers that don't need to tell
+    /// workers apart
+    pub fn new_simple<F>(f: F, size: usize) -> Self
+    where
+        F: Fn() -> S,
+    {
+        Self::new(move |_| f(), size)
     }
 
     /// execute a closure by the thread pool
@@ -58,29 +262,92 @@ impl<S: Send + 'static> ThreadPool<S> {
         use std::mem;
         use std::panic;
 
+        let blocker = Blocker::current();
         let mut ret = None;
         {
             let clo: Box<dyn FnOnce(&mut S) + Send> = Box::new(|s: &mut S| {
                 // this would be run in a worker thread
                 ret = Some(panic::catch_unwind(panic::AssertUnwindSafe(|| f(s))));
+                blocker.unpark();
             });
             let clo: Box<dyn FnOnce(&mut S) + Send + 'static> = unsafe { mem::transmute(clo) };
-            self.queue_tx
-                .send(clo)
-                .expect("failed to send to work queue");
-            coroutine::sleep(::std::time::Duration::from_secs(1));
+            self.submit(clo);
         }
 
-        ret.unwrap().unwrap()
+        // we can't use the `join()` API here, it will block the thread!
+        // we need catch the panic inside `f`, or we may wait forever!
+        match blocker.park(None) {
+            Ok(_) => match ret.expect("ret not set") {
+                Ok(ret) => ret,
+                Err(panic) => panic::resume_unwind(panic),
+            },
+            Err(_) => {
+                // impossible be a timeout err
+                // cancel happened, we do nothing here
+                coroutine::trigger_cancel_panic();
+            }
+        }
+    }
+
+    /// like `join`, but gives up waiting after `dur` and returns `None`
+    /// instead of blocking the coroutine forever.
+    ///
+    /// the worker thread keeps running the closure in the background after
+    /// a timeout, so the result slot is kept alive in an `Arc` for its late
+    /// write -- `T`'s destructor may end up running on the worker thread
+    /// instead of the caller's. because the closure may keep running after
+    /// this call returns, `F` and `T` must be `'static`, same as
+    /// `thread::spawn`.
+    pub fn join_timeout<F, T>(&self, f: F, dur: Duration) -> Option<T>
+    where
+        F: FnOnce(&mut S) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        use std::panic;
+
+        let blocker = Blocker::current();
+        let ret = Arc::new(Mutex::new(None));
+        let ret_worker = ret.clone();
+        let blocker_worker = blocker.clone();
+        {
+            let clo: Job<S> = Box::new(move |s: &mut S| {
+                // this would be run in a worker thread
+                *ret_worker.lock().unwrap() =
+                    Some(panic::catch_unwind(panic::AssertUnwindSafe(|| f(s))));
+                blocker_worker.unpark();
+            });
+            self.submit(clo);
+        }
+
+        match blocker.park(Some(dur)) {
+            Ok(_) => match ret.lock().unwrap().take().expect("ret not set") {
+                Ok(ret) => Some(ret),
+                Err(panic) => panic::resume_unwind(panic),
+            },
+            Err(coroutine::ParkError::Timeout) => None,
+            Err(coroutine::ParkError::Canceled) => {
+                // cancel happened, we do nothing here
+                coroutine::trigger_cancel_panic();
+            }
+        }
+    }
+
+    // push a job for a worker to run, waking an idle worker if needed
+    fn submit(&self, job: Job<S>) {
+        self.registry.submit(job);
     }
 }
 
 impl<S> Drop for ThreadPool<S> {
     fn drop(&mut self) {
-        // first need to destroy the tx side so that others will return
-        // just substitude with a dummy one
-        let (tx, _) = mpmc::channel();
-        self.queue_tx = tx;
+        // signal shutdown and keep waking workers until all queued work
+        // has actually drained, then let them exit
+        self.registry.shutdown.store(true, Ordering::Release);
+        while !self.registry.is_drained() {
+            self.registry.sleep.wake_all();
+            thread::yield_now();
+        }
+        self.registry.sleep.wake_all();
 
         // wait all the worker returns
         for thread in self.threads.iter_mut() {
@@ -95,11 +362,76 @@ mod tests {
 
     #[test]
     fn thread_pool() {
-        let pool = ThreadPool::new(|| 0, 4);
+        let pool = ThreadPool::new(|_| 0, 4);
         let a = pool.join(|s| {
             *s += 1;
             *s
         });
         assert_eq!(a, 1);
     }
+
+    #[test]
+    fn thread_pool_many_jobs() {
+        // `S` is per-worker, so a shared counter (not the per-worker state)
+        // is what proves every submitted job actually ran
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let pool = ThreadPool::new(|_| 0, 4);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let count = count.clone();
+            pool.join(move |_s| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn thread_pool_join_timeout_ok() {
+        let pool = ThreadPool::new(|_| 0, 4);
+        let a = pool.join_timeout(
+            |s| {
+                *s += 1;
+                *s
+            },
+            Duration::from_secs(1),
+        );
+        assert_eq!(a, Some(1));
+    }
+
+    #[test]
+    fn thread_pool_join_timeout_expires() {
+        let pool = ThreadPool::new(|_| 0, 4);
+        let a = pool.join_timeout(
+            |_s| {
+                thread::sleep(Duration::from_secs(1));
+                10
+            },
+            Duration::from_millis(10),
+        );
+        assert_eq!(a, None);
+    }
+
+    #[test]
+    fn new_passes_each_worker_its_index() {
+        use std::collections::HashSet;
+
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let seen_init = seen.clone();
+        let _pool = ThreadPool::new(
+            move |i| {
+                seen_init.lock().unwrap().insert(i);
+            },
+            4,
+        );
+        assert_eq!(*seen.lock().unwrap(), (0..4).collect());
+    }
+
+    #[test]
+    fn worker_threads_are_named() {
+        let pool = ThreadPool::new(|_| 0, 2);
+        let name = pool.join(|_s| thread::current().name().unwrap_or_default().to_string());
+        assert!(name.starts_with("may_thread-pool-"));
+    }
 }